@@ -15,7 +15,6 @@
 #[macro_use]
 extern crate nom;
 extern crate clap;
-extern crate byteorder;
 extern crate point_viewer;
 extern crate scoped_pool;
 extern crate pbr;
@@ -25,59 +24,28 @@ extern crate json;
 use point_viewer::Point;
 use point_viewer::math::{Vector3f, BoundingBox};
 use point_viewer::octree;
+use point_viewer::octree::{Compression, Meta, NodeWriter};
 use point_viewer::pts::PtsPointStream;
+use point_viewer::schema::Schema;
 
-use byteorder::{LittleEndian, WriteBytesExt};
 use pbr::{ProgressBar};
 use scoped_pool::{Scope, Pool};
 use std::collections::{HashSet, HashMap};
 use std::fs::{self, File};
-use std::io::{BufWriter, Write, Stdout};
+use std::io::{Write, Stdout};
 use std::cmp;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 
 const UPDATE_COUNT: i64 = 100000;
 
-#[derive(Debug)]
-struct NodeWriter {
-    writer: BufWriter<File>,
-    path: PathBuf,
-    num_points: i64,
-}
-
-impl Drop for NodeWriter {
-    fn drop(&mut self) {
-        // If we did not write anything into this node, it should not exist.
-        if self.num_points == 0 {
-            // We are ignoring deletion errors here in case the file is already gone.
-            let _ = fs::remove_file(&self.path);
-        }
+/// A node is split into children once it holds at least this many points.
+const SPLIT_THRESHOLD: i64 = 100000;
 
-        // TODO(hrapp): Add some sanity checks that we do not have nodes with ridiculously low
-        // amount of points laying around?
-    }
-}
-
-impl NodeWriter {
-    fn new(path: PathBuf) -> Self {
-        NodeWriter {
-            writer: BufWriter::new(File::create(&path).unwrap()),
-            path: path,
-            num_points: 0,
-        }
-    }
-
-    pub fn write(&mut self, p: &Point) {
-        self.writer.write_f32::<LittleEndian>(p.position.x).unwrap();
-        self.writer.write_f32::<LittleEndian>(p.position.y).unwrap();
-        self.writer.write_f32::<LittleEndian>(p.position.z).unwrap();
-        self.writer.write_u8(p.r).unwrap();
-        self.writer.write_u8(p.g).unwrap();
-        self.writer.write_u8(p.b).unwrap();
-        self.num_points += 1;
-    }
-}
+/// When appending to an existing octree, a leaf whose appended points exceed this fraction of
+/// its original size is split into children and its subtree rewritten from scratch, the same as
+/// a leaf that reaches `SPLIT_THRESHOLD`, even when it holds fewer points than that.
+const STALE_REWRITE_RATIO: f64 = 0.5;
 
 struct SplittedNode {
     name: String,
@@ -89,6 +57,8 @@ fn split<PointIterator: Iterator<Item = Point>>(output_directory: &Path,
                                                 name: &str,
                                                 bounding_box: &BoundingBox,
                                                 stream: PointIterator,
+                                                compression: Compression,
+                                                schema: &Schema,
                                                 mut progress: Option<SendingProgressReporter>)
                                                 -> Vec<SplittedNode> {
     let mut children: Vec<Option<NodeWriter>> = vec![None, None, None, None, None, None, None,
@@ -97,11 +67,13 @@ fn split<PointIterator: Iterator<Item = Point>>(output_directory: &Path,
         if num_point % UPDATE_COUNT as usize == 0 {
             progress.as_mut().map(|s| s.add(UPDATE_COUNT as u64));
         }
-        let child_index = get_child_index(&bounding_box, &p.position);
+        let child_index = octree::get_child_index(&bounding_box, &p.position);
         if children[child_index as usize].is_none() {
             children[child_index as usize] =
                 Some(NodeWriter::new(octree::node_path(output_directory,
-                                               &octree::child_node_name(name, child_index as u8))));
+                                               &octree::child_node_name(name, child_index as u8)),
+                                      compression,
+                                      schema.clone()));
         }
         children[child_index as usize].as_mut().unwrap().write(&p);
     }
@@ -120,7 +92,7 @@ fn split<PointIterator: Iterator<Item = Point>>(output_directory: &Path,
 
         rv.push(SplittedNode {
             name: octree::child_node_name(name, child_index as u8),
-            num_points: c.num_points,
+            num_points: c.num_points(),
             bounding_box: octree::get_child_bounding_box(&bounding_box, child_index as u8),
         });
     }
@@ -129,12 +101,33 @@ fn split<PointIterator: Iterator<Item = Point>>(output_directory: &Path,
     rv
 }
 
-fn get_child_index(bounding_box: &BoundingBox, v: &Vector3f) -> u8 {
-    let center = bounding_box.center();
-    let gt_x = v.x > center.x;
-    let gt_y = v.y > center.y;
-    let gt_z = v.z > center.z;
-    (gt_x as u8) << 2 | (gt_y as u8) << 1 | gt_z as u8
+/// Splits `name` and keeps splitting any resulting child that is still at or over
+/// `SPLIT_THRESHOLD`, the same invariant `split_node` maintains for a full build - used by
+/// `append`, which does its splitting synchronously rather than through the `Scope`/`Pool`
+/// machinery `split_node` uses.
+fn split_recursively<PointIterator: Iterator<Item = Point>>(output_directory: &Path,
+                                                             name: &str,
+                                                             bounding_box: &BoundingBox,
+                                                             points: PointIterator,
+                                                             compression: Compression,
+                                                             schema: &Schema)
+                                                             -> Vec<SplittedNode> {
+    let children = split(output_directory, name, bounding_box, points, compression, schema, None);
+    let mut leaves = Vec::new();
+    for child in children {
+        if child.num_points >= SPLIT_THRESHOLD {
+            let stream = octree::PointStream::from_blob(&octree::node_path(output_directory, &child.name), schema);
+            leaves.extend(split_recursively(output_directory,
+                                             &child.name,
+                                             &child.bounding_box,
+                                             stream,
+                                             compression,
+                                             schema));
+        } else {
+            leaves.push(child);
+        }
+    }
+    leaves
 }
 
 struct SendingProgressReporter {
@@ -179,6 +172,8 @@ fn split_node<'a, 'b: 'a, PointIterator: Iterator<Item = Point>>(scope: &Scope<'
                                                                  output_directory: &'b Path,
                                                                  node: SplittedNode,
                                                                  stream: PointIterator,
+                                                                 compression: Compression,
+                                                                 schema: Schema,
                                                                  leaf_nodes_sender: mpsc::Sender<String>,
                                                                  progress_sender: mpsc::Sender<Status>) {
     let progress = stream.size_hint().1.map(|size| {
@@ -186,17 +181,19 @@ fn split_node<'a, 'b: 'a, PointIterator: Iterator<Item = Point>>(scope: &Scope<'
                 node.name.clone(), progress_sender.clone(), size as i64)
     });
 
-    let children = split(output_directory, &node.name, &node.bounding_box, stream, progress);
+    let children = split(output_directory, &node.name, &node.bounding_box, stream, compression, &schema, progress);
     let (leaf_nodes, split_nodes): (Vec<_>, Vec<_>) = children.into_iter()
-        .partition(|n| n.num_points < 100000);
+        .partition(|n| n.num_points < SPLIT_THRESHOLD);
 
     for child in split_nodes {
         let leaf_nodes_sender_clone = leaf_nodes_sender.clone();
         let progress_sender_clone = progress_sender.clone();
+        let child_schema = schema.clone();
         scope.recurse(move |scope| {
             let stream = octree::PointStream::from_blob(&octree::node_path(output_directory,
-                                                                           &child.name));
-            split_node(scope, output_directory, child, stream, leaf_nodes_sender_clone, progress_sender_clone);
+                                                                           &child.name),
+                                                          &child_schema);
+            split_node(scope, output_directory, child, stream, compression, child_schema, leaf_nodes_sender_clone, progress_sender_clone);
         });
     }
 
@@ -205,30 +202,6 @@ fn split_node<'a, 'b: 'a, PointIterator: Iterator<Item = Point>>(scope: &Scope<'
     }
 }
 
-fn subsample_children_into(output_directory: &Path, node_name: &str) {
-    let mut parent = NodeWriter::new(octree::node_path(output_directory, node_name));
-
-    println!("Creating {} from subsampling children...", node_name);
-    for i in 0..8 {
-        let child_name = octree::child_node_name(node_name, i);
-        let path = octree::node_path(output_directory, &child_name);
-        if !path.exists() {
-            continue;
-        }
-        let points: Vec<_> = octree::PointStream::from_blob(&path)
-            .collect();
-        let mut child = NodeWriter::new(octree::node_path(output_directory, &child_name));
-        for (idx, p) in points.into_iter().enumerate() {
-            if idx % 8 == 0 {
-                parent.write(&p);
-            } else {
-                child.write(&p);
-            }
-        }
-
-    }
-}
-
 #[derive(Debug)]
 enum InputFile {
     Ply(PathBuf),
@@ -243,6 +216,15 @@ struct Status {
     num_points: i64,
 }
 
+/// Determines which attributes are present in `input`, so the octree can be written with a
+/// schema that matches instead of silently dropping anything beyond position and color.
+fn determine_schema(input: &InputFile) -> Schema {
+    match *input {
+        InputFile::Ply(ref filename) => octree::PointStream::from_ply(filename).schema().clone(),
+        InputFile::Pts(ref filename) => PtsPointStream::new(filename).schema(),
+    }
+}
+
 fn make_stream(input: &InputFile) -> (Box<Iterator<Item = Point>>, Option<pbr::ProgressBar<Stdout>>) {
     let stream: Box<Iterator<Item=Point>> = match *input {
         InputFile::Ply(ref filename) => {
@@ -284,7 +266,15 @@ fn main() {
                 clap::Arg::with_name("input")
                     .help("PLY/PTS file to parse for the points.")
                     .index(1)
-                    .required(true)])
+                    .required(true),
+                clap::Arg::with_name("compress")
+                    .help("Compress node blobs with Yaz0 before writing them to disk.")
+                    .long("compress"),
+                clap::Arg::with_name("append")
+                    .help("Add the points from the input file to the octree in \
+                           output_directory instead of rebuilding it from scratch. \
+                           output_directory must already contain an octree.")
+                    .long("append")])
         .get_matches();
 
     let output_directory = &PathBuf::from(matches.value_of("output_directory").unwrap());
@@ -298,6 +288,17 @@ fn main() {
         }
     };
 
+    if matches.is_present("append") {
+        append(output_directory, &input);
+        return;
+    }
+
+    let compression = if matches.is_present("compress") {
+        Compression::Yaz0
+    } else {
+        Compression::None
+    };
+    let schema = determine_schema(&input);
 
     let mut num_total_points = 0i64;
     let bounding_box = {
@@ -323,27 +324,18 @@ fn main() {
 
     // Ignore errors, maybe directory is already there.
     let _ = fs::create_dir(output_directory);
-    let meta = object!{
-        "version" => 1,
-        "bounding_box" => object!{
-            "min_x" => bounding_box.min.x,
-            "min_y" => bounding_box.min.y,
-            "min_z" => bounding_box.min.z,
-            "max_x" => bounding_box.max.x,
-            "max_y" => bounding_box.max.y,
-            "max_z" => bounding_box.max.z
-        }
-    };
-    File::create(&output_directory.join("meta.json"))
-        .unwrap()
-        .write_all(&meta.pretty(4).as_bytes())
-        .unwrap();
+    Meta {
+        bounding_box,
+        compression,
+        schema: schema.clone(),
+    }.write(output_directory);
 
     println!("Creating octree structure.");
     let pool = Pool::new(10);
 
     let (leaf_nodes_sender, leaf_nodes_receiver) = mpsc::channel();
     let (progress_sender, progress_receiver) = mpsc::channel::<Status>();
+    let root_schema = schema.clone();
     pool.scoped(move |scope| {
         scope.execute(move || {
             report_progress(progress_receiver, "Splitting:");
@@ -355,11 +347,20 @@ fn main() {
             bounding_box: bounding_box,
             num_points: num_total_points,
         };
-        split_node(scope, output_directory, root, root_stream, leaf_nodes_sender.clone(), progress_sender.clone());
+        split_node(scope, output_directory, root, root_stream, compression, root_schema, leaf_nodes_sender.clone(), progress_sender.clone());
     });
 
-    let mut leaf_nodes: Vec<_> = leaf_nodes_receiver.into_iter().collect();
+    let leaf_nodes: Vec<_> = leaf_nodes_receiver.into_iter().collect();
+    resubsample_ancestors(&pool, output_directory, leaf_nodes, compression, &schema);
+}
 
+/// Re-derives every node whose subtree contains one of `leaf_nodes`, walking up the tree one
+/// level at a time so that all nodes on the same level can be subsampled in parallel.
+fn resubsample_ancestors(pool: &Pool,
+                         output_directory: &Path,
+                         mut leaf_nodes: Vec<String>,
+                         compression: Compression,
+                         schema: &Schema) {
     // Sort by length of node name, longest first. A node with the same length name as another are
     // on the same tree level and can be subsampled in parallel.
     leaf_nodes.sort_by(|a, b| b.len().cmp(&a.len()));
@@ -384,11 +385,179 @@ fn main() {
         }
 
         pool.scoped(move |scope| {
-            for parent_name in parent_names {
+            for parent_name in &parent_names {
                 scope.execute(move || {
-                    subsample_children_into(output_directory, &parent_name);
+                    octree::subsample_children_into(output_directory, parent_name, compression, schema);
                 });
             }
         });
     }
+}
+
+/// How many points a leaf held and how many have been appended to it since the last time it was
+/// fully rewritten from scratch, persisted alongside the octree so repeated `append` runs share
+/// the same stale-data bookkeeping.
+#[derive(Debug, Clone, Copy)]
+struct AppendStats {
+    original: i64,
+    appended: i64,
+}
+
+fn append_stats_path(output_directory: &Path) -> PathBuf {
+    output_directory.join("append_stats.json")
+}
+
+fn read_append_stats(output_directory: &Path) -> HashMap<String, AppendStats> {
+    let path = append_stats_path(output_directory);
+    if !path.exists() {
+        return HashMap::new();
+    }
+    let contents = fs::read_to_string(path).unwrap();
+    let parsed = json::parse(&contents).unwrap();
+    let mut stats = HashMap::new();
+    for (name, entry) in parsed.entries() {
+        stats.insert(name.to_string(),
+                      AppendStats {
+                          original: entry["original"].as_i64().unwrap(),
+                          appended: entry["appended"].as_i64().unwrap(),
+                      });
+    }
+    stats
+}
+
+fn write_append_stats(output_directory: &Path, stats: &HashMap<String, AppendStats>) {
+    let mut obj = json::JsonValue::new_object();
+    for (name, s) in stats {
+        obj[name.as_str()] = object!{
+            "original" => s.original,
+            "appended" => s.appended,
+        };
+    }
+    File::create(&append_stats_path(output_directory))
+        .unwrap()
+        .write_all(&obj.pretty(4).as_bytes())
+        .unwrap();
+}
+
+/// Walks down from the root, following existing child node files, until it reaches a node that
+/// has not been split - that node is the leaf `point` belongs to. If a split node has no child
+/// for `point` yet, that missing child is returned as a new, empty leaf.
+fn find_leaf(output_directory: &Path, root_bounding_box: &BoundingBox, point: &Vector3f) -> String {
+    let mut name = "r".to_string();
+    let mut bounding_box = *root_bounding_box;
+    loop {
+        let child_index = octree::get_child_index(&bounding_box, point);
+        let child_name = octree::child_node_name(&name, child_index);
+        if !octree::node_path(output_directory, &child_name).exists() {
+            let is_split = (0..8).any(|i| {
+                octree::node_path(output_directory, &octree::child_node_name(&name, i)).exists()
+            });
+            return if is_split { child_name } else { name };
+        }
+        name = child_name;
+        bounding_box = octree::get_child_bounding_box(&bounding_box, child_index);
+    }
+}
+
+/// Appends all points from `input` into the octree in `output_directory` without rebuilding it
+/// from scratch: existing points are left untouched, new points are routed to their target leaf
+/// and only the subtrees that actually changed are re-subsampled.
+fn append(output_directory: &Path, input: &InputFile) {
+    let Meta { bounding_box, compression, schema } = Meta::read(output_directory);
+
+    // Every existing node's bounding box is implicitly defined by repeatedly bisecting the root
+    // bounding box, so growing the root box would silently move the octant boundaries every
+    // existing node was actually split on. Rather than re-route or rebuild the existing tree to
+    // match a new root box, require a full rebuild whenever new points don't already fit.
+    let (stream, _) = make_stream(input);
+    for p in stream {
+        if !bounding_box.contains(&p.position) {
+            panic!("Point {:?} lies outside the existing octree's bounding box {:?}. Appending \
+                    points that extend the tree's coverage would invalidate the bounding boxes \
+                    existing nodes were split on; rebuild the octree from scratch instead.",
+                   p.position, bounding_box);
+        }
+    }
+
+    let mut new_points_by_leaf: HashMap<String, Vec<Point>> = HashMap::new();
+    let (stream, mut progress_bar) = make_stream(input);
+    if let Some(ref mut progress_bar) = progress_bar {
+        progress_bar.message("Routing points to leaves: ");
+    }
+    for (i, p) in stream.enumerate() {
+        let leaf = find_leaf(output_directory, &bounding_box, &p.position);
+        new_points_by_leaf.entry(leaf).or_insert_with(Vec::new).push(p);
+        if i % UPDATE_COUNT as usize == 0 {
+            if let Some(ref mut progress_bar) = progress_bar {
+                progress_bar.add(UPDATE_COUNT as u64);
+            }
+        }
+    }
+
+    let mut stats = read_append_stats(output_directory);
+    let mut dirty_leaves = Vec::new();
+
+    for (leaf_name, new_points) in new_points_by_leaf {
+        let path = octree::node_path(output_directory, &leaf_name);
+        let existing_points: Vec<Point> = if path.exists() {
+            octree::PointStream::from_blob(&path, &schema).collect()
+        } else {
+            Vec::new()
+        };
+
+        let leaf_stats = stats.get(&leaf_name).cloned().unwrap_or(AppendStats {
+            original: existing_points.len() as i64,
+            appended: 0,
+        });
+        let appended = leaf_stats.appended + new_points.len() as i64;
+        let is_stale =
+            appended as f64 > STALE_REWRITE_RATIO * (leaf_stats.original.max(1) as f64);
+
+        let mut all_points = existing_points;
+        all_points.extend(new_points);
+
+        if is_stale || all_points.len() as i64 >= SPLIT_THRESHOLD {
+            // Either too much of this leaf is new data, or it grew past the split threshold:
+            // rewrite the whole subtree from scratch rather than patching it in place.
+            let leaf_bounding_box = octree::node_bounding_box(&bounding_box, &leaf_name);
+            let children = split_recursively(output_directory,
+                                              &leaf_name,
+                                              &leaf_bounding_box,
+                                              all_points.into_iter(),
+                                              compression,
+                                              &schema);
+            for child in children {
+                stats.remove(&child.name);
+                stats.insert(child.name.clone(),
+                              AppendStats {
+                                  original: child.num_points,
+                                  appended: 0,
+                              });
+                dirty_leaves.push(child.name);
+            }
+            stats.remove(&leaf_name);
+        } else {
+            let mut writer = NodeWriter::new(path, compression, schema.clone());
+            for p in &all_points {
+                writer.write(p);
+            }
+            stats.insert(leaf_name.clone(),
+                          AppendStats {
+                              original: leaf_stats.original,
+                              appended,
+                          });
+            dirty_leaves.push(leaf_name);
+        }
+    }
+
+    write_append_stats(output_directory, &stats);
+    Meta {
+        bounding_box,
+        compression,
+        schema: schema.clone(),
+    }.write(output_directory);
+
+    println!("Re-subsampling {} dirty subtree(s).", dirty_leaves.len());
+    let pool = Pool::new(10);
+    resubsample_ancestors(&pool, output_directory, dirty_leaves, compression, &schema);
 }
\ No newline at end of file
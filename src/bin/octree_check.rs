@@ -0,0 +1,481 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks an octree written by `build_octree` for on-disk consistency, and can repair the
+//! problems that commonly follow an interrupted build: missing parent nodes and orphaned,
+//! zero-point node files.
+
+extern crate clap;
+extern crate point_viewer;
+
+use point_viewer::octree::{self, Meta, NodeWriter};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How far off a parent's point count may be from 1/8th of its children's total and still count
+/// as a valid subsampling, allowing for the rounding that comes from taking every 8th point.
+const SUBSAMPLING_SLACK: f64 = 0.34;
+
+#[derive(Debug)]
+enum Problem {
+    BadStride { node: String, byte_len: usize, stride: usize },
+    PointOutsideBoundingBox { node: String },
+    MissingParent { node: String },
+    ZeroPointNode { node: String },
+    BadSubsamplingRatio { node: String, parent_points: i64, children_points: i64 },
+}
+
+impl Problem {
+    fn node_name(&self) -> &str {
+        match self {
+            Problem::BadStride { node, .. } => node,
+            Problem::PointOutsideBoundingBox { node } => node,
+            Problem::MissingParent { node } => node,
+            Problem::ZeroPointNode { node } => node,
+            Problem::BadSubsamplingRatio { node, .. } => node,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Problem::BadStride { byte_len, stride, .. } => {
+                format!("blob is {} bytes, not a multiple of the {}-byte point stride",
+                        byte_len, stride)
+            }
+            Problem::PointOutsideBoundingBox { .. } => {
+                "contains a point outside its node's bounding box".to_string()
+            }
+            Problem::MissingParent { .. } => {
+                "has children on disk but no node file of its own".to_string()
+            }
+            Problem::ZeroPointNode { .. } => "node file exists but holds zero points".to_string(),
+            Problem::BadSubsamplingRatio { parent_points, children_points, .. } => {
+                format!("holds {} points, expected roughly 1/8 of its children's {}",
+                        parent_points, children_points)
+            }
+        }
+    }
+}
+
+/// All node names with a blob file in `output_directory`, found by scanning the directory rather
+/// than walking down from the root, so a node whose parent file is missing is still found.
+fn discover_nodes(output_directory: &Path) -> Vec<String> {
+    let mut nodes = Vec::new();
+    for entry in fs::read_dir(output_directory).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|s| s.to_str()) != Some("xyz") {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+            nodes.push(name.to_string());
+        }
+    }
+    nodes
+}
+
+/// Reads every discovered node's blob exactly once, decompressing it if necessary, so the checks
+/// below can reuse the same bytes instead of hitting disk again per node per check.
+fn read_all_blobs(output_directory: &Path, nodes: &[String]) -> HashMap<String, Vec<u8>> {
+    nodes.iter()
+        .map(|name| {
+            let path = octree::node_path(output_directory, name);
+            (name.clone(), octree::read_node_blob(&path))
+        })
+        .collect()
+}
+
+fn check(output_directory: &Path) -> Vec<Problem> {
+    let meta = Meta::read(output_directory);
+    let stride = meta.schema.stride();
+    let nodes = discover_nodes(output_directory);
+    let present: HashSet<String> = nodes.iter().cloned().collect();
+    let blobs = read_all_blobs(output_directory, &nodes);
+
+    let mut problems = Vec::new();
+
+    for name in &nodes {
+        let blob = &blobs[name];
+        if blob.len() % stride != 0 {
+            problems.push(Problem::BadStride {
+                node: name.clone(),
+                byte_len: blob.len(),
+                stride,
+            });
+            continue;
+        }
+
+        if blob.is_empty() {
+            problems.push(Problem::ZeroPointNode { node: name.clone() });
+            continue;
+        }
+
+        let bounding_box = octree::node_bounding_box(&meta.bounding_box, name);
+        let outside_box = octree::PointStream::from_data(blob.clone(), meta.schema.clone())
+            .any(|point| !bounding_box.contains(&point.position));
+        if outside_box {
+            problems.push(Problem::PointOutsideBoundingBox { node: name.clone() });
+        }
+    }
+
+    // A node's parent must have a blob of its own: `build_octree` creates it by subsampling
+    // all of the parent's children once splitting is done.
+    let mut missing_parents: Vec<String> = Vec::new();
+    for name in &nodes {
+        let parent_name = octree::parent_node_name(name);
+        if parent_name.is_empty() || present.contains(parent_name) {
+            continue;
+        }
+        if !missing_parents.iter().any(|p| p.as_str() == parent_name) {
+            missing_parents.push(parent_name.to_string());
+        }
+    }
+    for parent_name in missing_parents {
+        problems.push(Problem::MissingParent { node: parent_name });
+    }
+
+    for name in &nodes {
+        let parent_points = (blobs[name].len() / stride) as i64;
+        let children_points: i64 = (0..8)
+            .filter_map(|child_index| {
+                let child_name = octree::child_node_name(name, child_index);
+                blobs.get(&child_name).map(|blob| (blob.len() / stride) as i64)
+            })
+            .sum();
+        if children_points == 0 {
+            continue;
+        }
+        let expected = children_points as f64 / 8.;
+        if (parent_points as f64 - expected).abs() > expected * SUBSAMPLING_SLACK + 1. {
+            problems.push(Problem::BadSubsamplingRatio {
+                node: name.clone(),
+                parent_points,
+                children_points,
+            });
+        }
+    }
+
+    problems
+}
+
+fn verify(output_directory: &Path) {
+    let problems = check(output_directory);
+    for problem in &problems {
+        println!("{}: {}", problem.node_name(), problem.describe());
+    }
+    println!("{} problem(s) found.", problems.len());
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Merges `name`'s own points back into whichever child they were originally subsampled from,
+/// keyed by `get_child_index` against `name`'s own bounding box - the same routing `split` used
+/// when it first created the children. Without this, re-deriving a node whose ratio is off by
+/// calling `subsample_children_into` directly would treat the children as still holding their
+/// full, never-subsampled point sets, permanently discarding `name`'s current points and shrinking
+/// the children a little further on every repair run instead of converging.
+fn merge_back_into_children(output_directory: &Path, meta: &Meta, name: &str) {
+    let path = octree::node_path(output_directory, name);
+    if !path.exists() {
+        return;
+    }
+    let bounding_box = octree::node_bounding_box(&meta.bounding_box, name);
+    let points: Vec<_> = octree::PointStream::from_blob(&path, &meta.schema).collect();
+
+    let mut by_child: HashMap<u8, Vec<_>> = HashMap::new();
+    for point in points {
+        let child_index = octree::get_child_index(&bounding_box, &point.position);
+        by_child.entry(child_index).or_insert_with(Vec::new).push(point);
+    }
+
+    for (child_index, new_points) in by_child {
+        let child_path = octree::node_path(output_directory, &octree::child_node_name(name, child_index));
+        let mut all_points = if child_path.exists() {
+            octree::PointStream::from_blob(&child_path, &meta.schema).collect()
+        } else {
+            Vec::new()
+        };
+        all_points.extend(new_points);
+
+        let mut writer = NodeWriter::new(child_path, meta.compression, meta.schema.clone());
+        for p in &all_points {
+            writer.write(p);
+        }
+    }
+}
+
+/// The most levels of simultaneously-missing ancestors a single `repair` invocation will chase:
+/// re-deriving a missing node can only ever uncover its own parent as newly missing, so this
+/// bounds how many passes a legitimate multi-level gap could possibly need, rather than looping
+/// forever if `check` somehow never converges.
+const MAX_REPAIR_PASSES: usize = 64;
+
+/// Runs one pass of repair - deleting zero-point nodes, merging bad-ratio nodes' points back into
+/// their children, and re-deriving missing or just-merged-back parents - and returns whether it
+/// changed anything on disk.
+fn repair_pass(output_directory: &Path, meta: &Meta, problems: &[Problem]) -> bool {
+    let mut changed = false;
+
+    for problem in problems {
+        if let Problem::ZeroPointNode { node } = problem {
+            println!("Removing zero-point node {}.", node);
+            let _ = fs::remove_file(octree::node_path(output_directory, node));
+            changed = true;
+        }
+    }
+
+    let bad_ratio: Vec<String> = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::BadSubsamplingRatio { node, .. } => Some(node.clone()),
+            _ => None,
+        })
+        .collect();
+    for name in &bad_ratio {
+        merge_back_into_children(output_directory, meta, name);
+    }
+
+    // Re-derive every missing or just-merged-back parent from its children. This is the same
+    // operation `build_octree` uses when it first creates a parent out of its just-split
+    // children, so repairing converges to the same blob `build_octree` would have written.
+    let mut to_rederive: Vec<String> = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::MissingParent { node } => Some(node.clone()),
+            _ => None,
+        })
+        .chain(bad_ratio)
+        .collect();
+    to_rederive.sort();
+    to_rederive.dedup();
+    to_rederive.sort_by_key(|name| std::cmp::Reverse(name.len()));
+
+    if !to_rederive.is_empty() {
+        changed = true;
+    }
+    for name in &to_rederive {
+        octree::subsample_children_into(output_directory, name, meta.compression, &meta.schema);
+    }
+
+    changed
+}
+
+/// Repairs the problems `check` can find automatically. A single pass can only re-derive the
+/// parents it already knows are missing or inconsistent; re-deriving one of them can in turn
+/// reveal its own parent as newly missing (a tree with two or more consecutive ancestor levels
+/// gone), so this re-runs `check`/repair passes until nothing changes or `MAX_REPAIR_PASSES` is
+/// hit, rather than requiring callers to invoke `repair` repeatedly themselves.
+fn repair(output_directory: &Path) {
+    let meta = Meta::read(output_directory);
+
+    let mut remaining = Vec::new();
+    for pass in 0..MAX_REPAIR_PASSES {
+        let problems = check(output_directory);
+        if !repair_pass(output_directory, &meta, &problems) {
+            remaining = problems;
+            break;
+        }
+        if pass == MAX_REPAIR_PASSES - 1 {
+            println!("Stopping after {} repair passes without converging.", MAX_REPAIR_PASSES);
+            remaining = check(output_directory);
+        }
+    }
+
+    for problem in &remaining {
+        println!("{}: {}", problem.node_name(), problem.describe());
+    }
+    println!("{} problem(s) remain after repair.", remaining.len());
+}
+
+fn main() {
+    let matches = clap::App::new("octree_check")
+        .subcommand(clap::SubCommand::with_name("verify")
+            .about("Checks an octree for consistency and reports any problems found.")
+            .arg(clap::Arg::with_name("output_directory")
+                .help("Directory containing the octree to check.")
+                .long("output_directory")
+                .required(true)
+                .takes_value(true)))
+        .subcommand(clap::SubCommand::with_name("repair")
+            .about("Repairs the problems `verify` can find automatically: re-derives missing or \
+                    inconsistent parent nodes and deletes zero-point node files.")
+            .arg(clap::Arg::with_name("output_directory")
+                .help("Directory containing the octree to repair.")
+                .long("output_directory")
+                .required(true)
+                .takes_value(true)))
+        .get_matches();
+
+    match matches.subcommand() {
+        ("verify", Some(sub_matches)) => {
+            let output_directory = PathBuf::from(sub_matches.value_of("output_directory").unwrap());
+            verify(&output_directory);
+        }
+        ("repair", Some(sub_matches)) => {
+            let output_directory = PathBuf::from(sub_matches.value_of("output_directory").unwrap());
+            repair(&output_directory);
+        }
+        _ => {
+            eprintln!("{}", matches.usage());
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use point_viewer::math::{BoundingBox, Vector3f};
+    use point_viewer::octree::Compression;
+    use point_viewer::schema::{Attribute, DataType, Schema};
+    use point_viewer::Point;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("point_viewer_octree_check_test_{}_{}",
+                                                      std::process::id(),
+                                                      name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn position_only_schema() -> Schema {
+        Schema::new(vec![Attribute::new("position", DataType::F32, 3)])
+    }
+
+    fn point_at(x: f32, y: f32, z: f32) -> Point {
+        Point {
+            position: Vector3f::new(x, y, z),
+            ..Point::default()
+        }
+    }
+
+    fn write_meta(dir: &Path, bounding_box: BoundingBox, schema: &Schema) {
+        Meta {
+            bounding_box,
+            compression: Compression::None,
+            schema: schema.clone(),
+        }.write(dir);
+    }
+
+    fn write_node(dir: &Path, name: &str, schema: &Schema, points: &[Point]) {
+        let mut writer = NodeWriter::new(octree::node_path(dir, name), Compression::None, schema.clone());
+        for p in points {
+            writer.write(p);
+        }
+    }
+
+    #[test]
+    fn check_detects_bad_stride() {
+        let dir = test_dir("bad_stride");
+        let schema = position_only_schema();
+        write_meta(&dir, BoundingBox { min: Vector3f::new(0., 0., 0.), max: Vector3f::new(2., 2., 2.) }, &schema);
+        fs::write(octree::node_path(&dir, "r"), vec![0u8; 13]).unwrap();
+
+        let problems = check(&dir);
+        assert!(problems.iter().any(|p| matches!(p, Problem::BadStride { node, .. } if node == "r")));
+    }
+
+    #[test]
+    fn check_detects_point_outside_bounding_box() {
+        let dir = test_dir("outside_box");
+        let schema = position_only_schema();
+        write_meta(&dir, BoundingBox { min: Vector3f::new(0., 0., 0.), max: Vector3f::new(2., 2., 2.) }, &schema);
+        write_node(&dir, "r", &schema, &[point_at(5., 5., 5.)]);
+
+        let problems = check(&dir);
+        assert!(problems.iter().any(|p| matches!(p, Problem::PointOutsideBoundingBox { node } if node == "r")));
+    }
+
+    #[test]
+    fn check_detects_missing_parent() {
+        let dir = test_dir("missing_parent");
+        let schema = position_only_schema();
+        let bounding_box = BoundingBox { min: Vector3f::new(0., 0., 0.), max: Vector3f::new(2., 2., 2.) };
+        write_meta(&dir, bounding_box, &schema);
+        let child_box = octree::node_bounding_box(&bounding_box, "r0");
+        write_node(&dir, "r0", &schema, &[point_at(child_box.min.x, child_box.min.y, child_box.min.z)]);
+
+        let problems = check(&dir);
+        assert!(problems.iter().any(|p| matches!(p, Problem::MissingParent { node } if node == "r")));
+    }
+
+    #[test]
+    fn check_detects_bad_subsampling_ratio() {
+        let dir = test_dir("bad_ratio");
+        let schema = position_only_schema();
+        let bounding_box = BoundingBox { min: Vector3f::new(0., 0., 0.), max: Vector3f::new(2., 2., 2.) };
+        write_meta(&dir, bounding_box, &schema);
+        let child_box = octree::node_bounding_box(&bounding_box, "r0");
+        let child_points: Vec<Point> = (0..80)
+            .map(|_| point_at(child_box.min.x, child_box.min.y, child_box.min.z))
+            .collect();
+        write_node(&dir, "r0", &schema, &child_points);
+        // 80 children points should subsample down to roughly 10, not 1.
+        write_node(&dir, "r", &schema, &[point_at(bounding_box.min.x, bounding_box.min.y, bounding_box.min.z)]);
+
+        let problems = check(&dir);
+        assert!(problems.iter().any(|p| matches!(p, Problem::BadSubsamplingRatio { node, .. } if node == "r")));
+    }
+
+    #[test]
+    fn repair_rederives_a_missing_parent_from_its_children() {
+        let dir = test_dir("repair_missing_parent");
+        let schema = position_only_schema();
+        let bounding_box = BoundingBox { min: Vector3f::new(0., 0., 0.), max: Vector3f::new(2., 2., 2.) };
+        write_meta(&dir, bounding_box, &schema);
+        for child_index in 0..8u8 {
+            let name = octree::child_node_name("r", child_index);
+            let child_box = octree::node_bounding_box(&bounding_box, &name);
+            let points: Vec<Point> = (0..8)
+                .map(|_| point_at(child_box.min.x, child_box.min.y, child_box.min.z))
+                .collect();
+            write_node(&dir, &name, &schema, &points);
+        }
+        assert!(!octree::node_path(&dir, "r").exists());
+
+        repair(&dir);
+
+        assert!(octree::node_path(&dir, "r").exists());
+        let remaining = check(&dir);
+        assert!(!remaining.iter().any(|p| matches!(p, Problem::MissingParent { .. })));
+    }
+
+    #[test]
+    fn repair_rederives_two_consecutive_missing_ancestor_levels() {
+        let dir = test_dir("repair_two_missing_levels");
+        let schema = position_only_schema();
+        let bounding_box = BoundingBox { min: Vector3f::new(0., 0., 0.), max: Vector3f::new(2., 2., 2.) };
+        write_meta(&dir, bounding_box, &schema);
+        // Only "r0"'s children exist on disk; both "r0" and "r" are missing.
+        for child_index in 0..8u8 {
+            let name = octree::child_node_name("r0", child_index);
+            let child_box = octree::node_bounding_box(&bounding_box, &name);
+            let points: Vec<Point> = (0..8)
+                .map(|_| point_at(child_box.min.x, child_box.min.y, child_box.min.z))
+                .collect();
+            write_node(&dir, &name, &schema, &points);
+        }
+        assert!(!octree::node_path(&dir, "r0").exists());
+        assert!(!octree::node_path(&dir, "r").exists());
+
+        repair(&dir);
+
+        assert!(octree::node_path(&dir, "r0").exists());
+        assert!(octree::node_path(&dir, "r").exists());
+        let remaining = check(&dir);
+        assert!(!remaining.iter().any(|p| matches!(p, Problem::MissingParent { .. })));
+    }
+}
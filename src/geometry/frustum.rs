@@ -13,7 +13,11 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Frustum<S: BaseFloat> {
     world_from_clip: Matrix4<S>,
+    clip_from_world: Matrix4<S>,
     frustum: collision::Frustum<S>,
+    /// Set by `from_intrinsics`, since only a frustum built from pixel-space intrinsics has an
+    /// image for `project` to cull against.
+    image_size: Option<(S, S)>,
 }
 
 impl<S: BaseFloat> Frustum<S> {
@@ -25,9 +29,69 @@ impl<S: BaseFloat> Frustum<S> {
         let frustum = collision::Frustum::from_matrix4(clip_from_world).unwrap();
         Frustum {
             world_from_clip,
+            clip_from_world,
             frustum,
+            image_size: None,
         }
     }
+
+    /// Builds a frustum from an OpenCV-style pinhole camera intrinsic matrix
+    /// `[[fx, 0, cx], [0, fy, cy], [0, 0, 1]]` and the size in pixels of the image it was
+    /// calibrated for. `world_from_eye` places the eye - not the OpenCV camera - in the world; the
+    /// 180 deg rotation around the x axis described above is folded into `top`/`bottom` here, so
+    /// the `Perspective` handed to `new` is already in eye coordinates.
+    pub fn from_intrinsics(
+        fx: S,
+        fy: S,
+        cx: S,
+        cy: S,
+        image_width: S,
+        image_height: S,
+        near: S,
+        far: S,
+        world_from_eye: Isometry3<S>,
+    ) -> Self {
+        let clip_from_eye = Perspective {
+            left: -cx * near / fx,
+            right: (image_width - cx) * near / fx,
+            top: cy * near / fy,
+            bottom: (cy - image_height) * near / fy,
+            near,
+            far,
+        };
+        let mut frustum = Self::new(world_from_eye, clip_from_eye);
+        frustum.image_size = Some((image_width, image_height));
+        frustum
+    }
+
+    /// The inverse of `corners()`: projects `world_point` into the image `from_intrinsics` was
+    /// built for, returning `None` if the point is behind the camera, outside the near/far range,
+    /// or falls outside `[0, width) x [0, height)`. `None` if this frustum was not built with
+    /// `from_intrinsics`.
+    pub fn project(&self, world_point: Point3<S>) -> Option<(S, S, S)> {
+        let (width, height) = self.image_size?;
+        let clip = self.clip_from_world * world_point.to_homogeneous();
+        if clip.w <= S::zero() {
+            return None;
+        }
+        let two = S::one() + S::one();
+        let ndc_x = clip.x / clip.w;
+        let ndc_y = clip.y / clip.w;
+        let ndc_z = clip.z / clip.w;
+        if ndc_z < -S::one() || ndc_z > S::one() {
+            return None;
+        }
+        let pixel_x = (ndc_x + S::one()) / two * width;
+        // NDC y points up; pixel space has y down with the origin at the top-left.
+        let pixel_y = (S::one() - ndc_y) / two * height;
+        if pixel_x < S::zero() || pixel_x >= width || pixel_y < S::zero() || pixel_y >= height {
+            return None;
+        }
+        // The projection matrix sets clip.w = -z_eye, so this is already the point's distance in
+        // front of the camera.
+        let depth = clip.w;
+        Some((pixel_x, pixel_y, depth))
+    }
 }
 
 impl<S> PointCulling<S> for Frustum<S>
@@ -68,3 +132,36 @@ where
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity_pose() -> Isometry3<f64> {
+        Decomposed {
+            scale: 1.0,
+            rot: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            disp: Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    #[test]
+    fn from_intrinsics_projects_a_centered_point_to_the_principal_point() {
+        let frustum =
+            Frustum::from_intrinsics(100., 100., 50., 50., 100., 100., 1., 100., identity_pose());
+        let (pixel_x, pixel_y, depth) = frustum.project(Point3::new(0., 0., -5.)).unwrap();
+        assert!((pixel_x - 50.).abs() < 1e-9);
+        assert!((pixel_y - 50.).abs() < 1e-9);
+        assert!((depth - 5.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn project_rejects_points_behind_the_camera_and_outside_the_image() {
+        let frustum =
+            Frustum::from_intrinsics(100., 100., 50., 50., 100., 100., 1., 100., identity_pose());
+        // Behind the camera (positive z in eye space, since eye space looks down -z).
+        assert_eq!(frustum.project(Point3::new(0., 0., 5.)), None);
+        // In front of the camera, but off to the side far enough to fall outside the image.
+        assert_eq!(frustum.project(Point3::new(1000., 0., -5.)), None);
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Basic math types shared by the octree builder and the viewer.
+
+use cgmath::{BaseFloat, Decomposed, Point3, Quaternion, Vector3};
+use collision::Aabb3;
+
+pub type Vector3f = Vector3<f32>;
+
+/// An isometry (rotation + translation, no scale) taking points from `eye`
+/// space into `world` space.
+pub type Isometry3<S> = Decomposed<Vector3<S>, Quaternion<S>>;
+
+/// An object that knows which of its eight corners bound it in world space.
+pub trait Cuboid<S: BaseFloat> {
+    fn corners(&self) -> [Point3<S>; 8];
+}
+
+/// An object that can decide whether it contains points or intersects boxes.
+pub trait PointCulling<S: BaseFloat>: Sync + Send {
+    fn contains(&self, point: &Point3<S>) -> bool;
+    fn intersects_aabb3(&self, aabb: &Aabb3<S>) -> bool;
+}
+
+/// An axis aligned bounding box with `f32` precision, as used by the octree
+/// builder.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min: Vector3f,
+    pub max: Vector3f,
+}
+
+impl BoundingBox {
+    pub fn new() -> Self {
+        BoundingBox {
+            min: Vector3f::new(std::f32::MAX, std::f32::MAX, std::f32::MAX),
+            max: Vector3f::new(std::f32::MIN, std::f32::MIN, std::f32::MIN),
+        }
+    }
+
+    pub fn update(&mut self, point: &Vector3f) {
+        self.min.x = self.min.x.min(point.x);
+        self.min.y = self.min.y.min(point.y);
+        self.min.z = self.min.z.min(point.z);
+        self.max.x = self.max.x.max(point.x);
+        self.max.y = self.max.y.max(point.y);
+        self.max.z = self.max.z.max(point.z);
+    }
+
+    pub fn contains(&self, point: &Vector3f) -> bool {
+        point.x >= self.min.x && point.x <= self.max.x
+            && point.y >= self.min.y && point.y <= self.max.y
+            && point.z >= self.min.z && point.z <= self.max.z
+    }
+
+    pub fn center(&self) -> Vector3f {
+        Vector3f::new(
+            (self.min.x + self.max.x) / 2.,
+            (self.min.y + self.max.y) / 2.,
+            (self.min.z + self.max.z) / 2.,
+        )
+    }
+
+    /// Grows this box to be a cube, so that all octree children are cubes too.
+    pub fn make_cubic(&mut self) {
+        let edge_length = (self.max.x - self.min.x)
+            .max(self.max.y - self.min.y)
+            .max(self.max.z - self.min.z);
+        self.max = self.min
+            + Vector3f::new(edge_length, edge_length, edge_length);
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Naming and on-disk layout of octree nodes, and the reader that turns a
+//! node blob back into `Point`s.
+
+use crate::math::{BoundingBox, Vector3f};
+use crate::point::Point;
+use crate::schema::{Attribute, DataType, FromReader, Schema, ToWriter};
+use crate::yaz0;
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns the path of the node file called `name` inside `directory`.
+pub fn node_path(directory: &Path, name: &str) -> PathBuf {
+    directory.join(format!("{}.xyz", name))
+}
+
+/// Returns the name of the `child_index`'th child of `name`, e.g.
+/// `child_node_name("r", 3)` is `"r3"`.
+pub fn child_node_name(name: &str, child_index: u8) -> String {
+    format!("{}{}", name, child_index)
+}
+
+/// Returns the name of the parent of `name`, or the empty string if `name`
+/// is already the root.
+pub fn parent_node_name(name: &str) -> &str {
+    if name.is_empty() {
+        return name;
+    }
+    &name[0..name.len() - 1]
+}
+
+/// Returns the bounding box of the node called `name`, computed by walking down from the root
+/// bounding box of the octree through one `get_child_bounding_box` call per digit in `name`.
+pub fn node_bounding_box(root_bounding_box: &BoundingBox, name: &str) -> BoundingBox {
+    let mut bounding_box = *root_bounding_box;
+    for c in name.chars().skip(1) {
+        let child_index = c.to_digit(10).unwrap() as u8;
+        bounding_box = get_child_bounding_box(&bounding_box, child_index);
+    }
+    bounding_box
+}
+
+/// Returns which of the 8 children of a node with bounding box `bounding_box` contains `point`.
+pub fn get_child_index(bounding_box: &BoundingBox, point: &Vector3f) -> u8 {
+    let center = bounding_box.center();
+    let gt_x = point.x > center.x;
+    let gt_y = point.y > center.y;
+    let gt_z = point.z > center.z;
+    (gt_x as u8) << 2 | (gt_y as u8) << 1 | gt_z as u8
+}
+
+/// Returns the bounding box of the `child_index`'th child of a node whose
+/// bounding box is `bounding_box`.
+pub fn get_child_bounding_box(bounding_box: &BoundingBox, child_index: u8) -> BoundingBox {
+    let half_edge_length = (bounding_box.max.x - bounding_box.min.x) / 2.;
+    let center = bounding_box.center();
+
+    let mut min = center;
+    if child_index & 0b100 == 0 {
+        min.x -= half_edge_length;
+    }
+    if child_index & 0b010 == 0 {
+        min.y -= half_edge_length;
+    }
+    if child_index & 0b001 == 0 {
+        min.z -= half_edge_length;
+    }
+
+    BoundingBox {
+        min,
+        max: min + Vector3f::new(half_edge_length, half_edge_length, half_edge_length),
+    }
+}
+
+/// Whether a node blob is stored raw or wrapped in a Yaz0 container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Yaz0,
+}
+
+/// The octree-wide metadata persisted as `meta.json` in the output directory.
+#[derive(Debug, Clone)]
+pub struct Meta {
+    pub bounding_box: BoundingBox,
+    pub compression: Compression,
+    pub schema: Schema,
+}
+
+impl Meta {
+    fn path(output_directory: &Path) -> PathBuf {
+        output_directory.join("meta.json")
+    }
+
+    pub fn write(&self, output_directory: &Path) {
+        let meta = object!{
+            "version" => 1,
+            "bounding_box" => object!{
+                "min_x" => self.bounding_box.min.x,
+                "min_y" => self.bounding_box.min.y,
+                "min_z" => self.bounding_box.min.z,
+                "max_x" => self.bounding_box.max.x,
+                "max_y" => self.bounding_box.max.y,
+                "max_z" => self.bounding_box.max.z
+            },
+            "compression" => match self.compression {
+                Compression::None => "none",
+                Compression::Yaz0 => "yaz0",
+            },
+            "attributes" => self.schema.to_json(),
+        };
+        File::create(&Self::path(output_directory))
+            .unwrap()
+            .write_all(&meta.pretty(4).as_bytes())
+            .unwrap();
+    }
+
+    pub fn read(output_directory: &Path) -> Self {
+        let contents = fs::read_to_string(Self::path(output_directory)).unwrap();
+        let meta = json::parse(&contents).unwrap();
+        let bb = &meta["bounding_box"];
+        let bounding_box = BoundingBox {
+            min: Vector3f::new(bb["min_x"].as_f32().unwrap(),
+                                bb["min_y"].as_f32().unwrap(),
+                                bb["min_z"].as_f32().unwrap()),
+            max: Vector3f::new(bb["max_x"].as_f32().unwrap(),
+                                bb["max_y"].as_f32().unwrap(),
+                                bb["max_z"].as_f32().unwrap()),
+        };
+        let compression = match meta["compression"].as_str() {
+            Some("yaz0") => Compression::Yaz0,
+            _ => Compression::None,
+        };
+        let schema = if meta["attributes"].is_null() {
+            Schema::legacy()
+        } else {
+            Schema::from_json(&meta["attributes"])
+        };
+        Meta {
+            bounding_box,
+            compression,
+            schema,
+        }
+    }
+}
+
+/// Writes a single node's points out to its blob file, transparently wrapping them in a Yaz0
+/// container on `Drop` if requested, and removing the file entirely if nothing was ever written.
+#[derive(Debug)]
+pub struct NodeWriter {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    compression: Compression,
+    schema: Schema,
+    num_points: i64,
+}
+
+impl Drop for NodeWriter {
+    fn drop(&mut self) {
+        // If we did not write anything into this node, it should not exist.
+        if self.num_points == 0 {
+            // We are ignoring deletion errors here in case the file is already gone.
+            let _ = fs::remove_file(&self.path);
+            return;
+        }
+
+        // Wrap the raw blob we just streamed out in a Yaz0 container. This is a second pass
+        // over the node's data, but keeps the hot path above a simple, unbuffered append.
+        if self.compression == Compression::Yaz0 {
+            self.writer.flush().unwrap();
+            let raw = fs::read(&self.path).unwrap();
+            fs::write(&self.path, yaz0::compress(&raw)).unwrap();
+        }
+    }
+}
+
+impl NodeWriter {
+    pub fn new(path: PathBuf, compression: Compression, schema: Schema) -> Self {
+        NodeWriter {
+            writer: BufWriter::new(File::create(&path).unwrap()),
+            path: path,
+            compression: compression,
+            schema: schema,
+            num_points: 0,
+        }
+    }
+
+    pub fn write(&mut self, p: &Point) {
+        p.to_writer(&self.schema, &mut self.writer).unwrap();
+        self.num_points += 1;
+    }
+
+    pub fn num_points(&self) -> i64 {
+        self.num_points
+    }
+}
+
+/// Re-derives `node_name` by taking every 8th point out of each of its existing children. Used
+/// both while building an octree (to create parents from just-split children) and by `repair` to
+/// re-derive a parent node that is missing or inconsistent.
+pub fn subsample_children_into(output_directory: &Path,
+                               node_name: &str,
+                               compression: Compression,
+                               schema: &Schema) {
+    let mut parent = NodeWriter::new(node_path(output_directory, node_name),
+                                      compression,
+                                      schema.clone());
+
+    println!("Creating {} from subsampling children...", node_name);
+    for i in 0..8 {
+        let child_name = child_node_name(node_name, i);
+        let path = node_path(output_directory, &child_name);
+        if !path.exists() {
+            continue;
+        }
+        let points: Vec<_> = PointStream::from_blob(&path, schema).collect();
+        let mut child = NodeWriter::new(node_path(output_directory, &child_name),
+                                         compression,
+                                         schema.clone());
+        for (idx, p) in points.into_iter().enumerate() {
+            if idx % 8 == 0 {
+                parent.write(&p);
+            } else {
+                child.write(&p);
+            }
+        }
+    }
+}
+
+/// An iterator over the points contained in a node blob or an input file.
+pub struct PointStream {
+    cursor: Cursor<Vec<u8>>,
+    schema: Schema,
+    num_total_points: usize,
+    num_points_read: usize,
+}
+
+/// Reads a node blob off disk, transparently decompressing it first if it is Yaz0-compressed so
+/// that old, uncompressed octrees still load.
+pub fn read_node_blob<P: AsRef<Path>>(path: P) -> Vec<u8> {
+    let raw = fs::read(path).unwrap();
+    if yaz0::is_compressed(&raw) {
+        yaz0::decompress(&raw)
+    } else {
+        raw
+    }
+}
+
+impl PointStream {
+    /// Reads a node blob, transparently decompressing it first if it is
+    /// Yaz0-compressed so that old, uncompressed octrees still load.
+    pub fn from_blob<P: AsRef<Path>>(path: P, schema: &Schema) -> Self {
+        Self::from_data(read_node_blob(path), schema.clone())
+    }
+
+    /// Reads points out of a (currently: ASCII) PLY file, inferring a `Schema` from whichever of
+    /// the `vertex` element's `x y z`, `red green blue`, `intensity`, `nx ny nz`, `gps_time` and
+    /// `classification` properties the header declares, in whatever order the header lists them.
+    pub fn from_ply<P: AsRef<Path>>(path: P) -> Self {
+        let contents = fs::read_to_string(path).unwrap();
+        let mut lines = contents.lines();
+        let mut num_vertices = 0usize;
+        let mut properties = Vec::new();
+        let mut in_vertex_element = false;
+        for line in &mut lines {
+            if let Some(rest) = line.strip_prefix("element vertex ") {
+                num_vertices = rest.trim().parse().unwrap();
+                in_vertex_element = true;
+                continue;
+            }
+            if line.starts_with("element ") {
+                in_vertex_element = false;
+                continue;
+            }
+            if in_vertex_element {
+                if let Some(rest) = line.strip_prefix("property ") {
+                    let fields: Vec<&str> = rest.split_whitespace().collect();
+                    properties.push(fields[1].to_string());
+                }
+            }
+            if line == "end_header" {
+                break;
+            }
+        }
+
+        let has = |name: &str| properties.iter().any(|p| p == name);
+        let has_color = has("red") && has("green") && has("blue");
+        let has_intensity = has("intensity");
+        let has_normal = has("nx") && has("ny") && has("nz");
+        let has_gps_time = has("gps_time");
+        let has_classification = has("classification");
+
+        let mut attributes = vec![Attribute::new("position", DataType::F32, 3)];
+        if has_color {
+            attributes.push(Attribute::new("color", DataType::U8, 3));
+        }
+        if has_intensity {
+            attributes.push(Attribute::new("intensity", DataType::F32, 1));
+        }
+        if has_normal {
+            attributes.push(Attribute::new("normal", DataType::F32, 3));
+        }
+        if has_gps_time {
+            attributes.push(Attribute::new("gps_time", DataType::F64, 1));
+        }
+        if has_classification {
+            attributes.push(Attribute::new("classification", DataType::U8, 1));
+        }
+        let schema = Schema::new(attributes);
+
+        let mut data = Vec::with_capacity(num_vertices * schema.stride());
+        for line in lines.by_ref().take(num_vertices) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let mut point = Point::default();
+            let mut normal = Vector3f::new(0., 0., 0.);
+            let parse_f32 = |s: &str| s.parse::<f32>().unwrap();
+            for (field, property) in fields.iter().copied().zip(&properties) {
+                match property.as_str() {
+                    "x" => point.position.x = parse_f32(field),
+                    "y" => point.position.y = parse_f32(field),
+                    "z" => point.position.z = parse_f32(field),
+                    "red" => point.r = field.parse().unwrap(),
+                    "green" => point.g = field.parse().unwrap(),
+                    "blue" => point.b = field.parse().unwrap(),
+                    "intensity" => point.intensity = Some(parse_f32(field)),
+                    "nx" => normal.x = parse_f32(field),
+                    "ny" => normal.y = parse_f32(field),
+                    "nz" => normal.z = parse_f32(field),
+                    "gps_time" => point.gps_time = Some(field.parse().unwrap()),
+                    "classification" => point.classification = Some(field.parse().unwrap()),
+                    _ => {}
+                }
+            }
+            if has_normal {
+                point.normal = Some(normal);
+            }
+            point.to_writer(&schema, &mut data).unwrap();
+        }
+
+        Self::from_data(data, schema)
+    }
+
+    /// Builds a stream directly from already-decompressed bytes, e.g. ones obtained from
+    /// `read_node_blob` and kept around for other uses instead of reading the file twice.
+    pub fn from_data(data: Vec<u8>, schema: Schema) -> Self {
+        let stride = schema.stride();
+        let num_total_points = data.len() / stride;
+        PointStream {
+            cursor: Cursor::new(data),
+            schema,
+            num_total_points,
+            num_points_read: 0,
+        }
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+}
+
+impl Iterator for PointStream {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        if self.num_points_read >= self.num_total_points {
+            return None;
+        }
+        self.num_points_read += 1;
+        Some(Point::from_reader(&self.schema, &mut self.cursor).unwrap())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.num_total_points - self.num_points_read;
+        (remaining, Some(remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn child_and_parent_names_round_trip() {
+        assert_eq!(child_node_name("r", 3), "r3");
+        assert_eq!(parent_node_name("r3"), "r");
+        assert_eq!(parent_node_name("r"), "");
+    }
+
+    #[test]
+    fn child_bounding_boxes_tile_the_parent() {
+        let parent = BoundingBox {
+            min: Vector3f::new(0., 0., 0.),
+            max: Vector3f::new(2., 2., 2.),
+        };
+        let child = get_child_bounding_box(&parent, 0b111);
+        assert_eq!(child.min, Vector3f::new(1., 1., 1.));
+        assert_eq!(child.max, Vector3f::new(2., 2., 2.));
+    }
+}
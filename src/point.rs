@@ -0,0 +1,138 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::math::Vector3f;
+use crate::schema::{DataType, FromReader, Schema, ToWriter};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+/// A single point as read from an input file or an octree node blob. `position` is always
+/// present; the remaining attributes are only set if the schema the point was read with (or is
+/// about to be written with) declares them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub position: Vector3f,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub intensity: Option<f32>,
+    pub normal: Option<Vector3f>,
+    pub gps_time: Option<f64>,
+    pub classification: Option<u8>,
+}
+
+impl Default for Point {
+    fn default() -> Self {
+        Point {
+            position: Vector3f::new(0., 0., 0.),
+            r: 0,
+            g: 0,
+            b: 0,
+            intensity: None,
+            normal: None,
+            gps_time: None,
+            classification: None,
+        }
+    }
+}
+
+impl ToWriter for Point {
+    fn to_writer<W: Write>(&self, schema: &Schema, writer: &mut W) -> io::Result<()> {
+        for attribute in &schema.attributes {
+            match (attribute.name.as_str(), attribute.data_type, attribute.count) {
+                ("position", DataType::F32, 3) => {
+                    writer.write_f32::<LittleEndian>(self.position.x)?;
+                    writer.write_f32::<LittleEndian>(self.position.y)?;
+                    writer.write_f32::<LittleEndian>(self.position.z)?;
+                }
+                ("color", DataType::U8, 3) => {
+                    writer.write_u8(self.r)?;
+                    writer.write_u8(self.g)?;
+                    writer.write_u8(self.b)?;
+                }
+                ("intensity", DataType::F32, 1) => {
+                    writer.write_f32::<LittleEndian>(self.intensity.unwrap_or(0.))?;
+                }
+                ("normal", DataType::F32, 3) => {
+                    let normal = self.normal.unwrap_or(Vector3f::new(0., 0., 0.));
+                    writer.write_f32::<LittleEndian>(normal.x)?;
+                    writer.write_f32::<LittleEndian>(normal.y)?;
+                    writer.write_f32::<LittleEndian>(normal.z)?;
+                }
+                ("gps_time", DataType::F64, 1) => {
+                    writer.write_f64::<LittleEndian>(self.gps_time.unwrap_or(0.))?;
+                }
+                ("classification", DataType::U8, 1) => {
+                    writer.write_u8(self.classification.unwrap_or(0))?;
+                }
+                _ => {
+                    // An attribute this build doesn't know how to populate, e.g. one written by a
+                    // newer version: write zeroed placeholder bytes so the schema's declared
+                    // stride still round-trips instead of crashing on an otherwise well-formed,
+                    // self-describing record.
+                    for _ in 0..attribute.byte_len() {
+                        writer.write_u8(0)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromReader for Point {
+    fn from_reader<R: Read>(schema: &Schema, reader: &mut R) -> io::Result<Self> {
+        let mut point = Point::default();
+        for attribute in &schema.attributes {
+            match (attribute.name.as_str(), attribute.data_type, attribute.count) {
+                ("position", DataType::F32, 3) => {
+                    point.position = Vector3f::new(
+                        reader.read_f32::<LittleEndian>()?,
+                        reader.read_f32::<LittleEndian>()?,
+                        reader.read_f32::<LittleEndian>()?,
+                    );
+                }
+                ("color", DataType::U8, 3) => {
+                    point.r = reader.read_u8()?;
+                    point.g = reader.read_u8()?;
+                    point.b = reader.read_u8()?;
+                }
+                ("intensity", DataType::F32, 1) => {
+                    point.intensity = Some(reader.read_f32::<LittleEndian>()?);
+                }
+                ("normal", DataType::F32, 3) => {
+                    point.normal = Some(Vector3f::new(
+                        reader.read_f32::<LittleEndian>()?,
+                        reader.read_f32::<LittleEndian>()?,
+                        reader.read_f32::<LittleEndian>()?,
+                    ));
+                }
+                ("gps_time", DataType::F64, 1) => {
+                    point.gps_time = Some(reader.read_f64::<LittleEndian>()?);
+                }
+                ("classification", DataType::U8, 1) => {
+                    point.classification = Some(reader.read_u8()?);
+                }
+                _ => {
+                    // An attribute this build doesn't know how to populate, e.g. one written by a
+                    // newer version: skip its bytes rather than crashing on an otherwise
+                    // well-formed, self-describing record.
+                    let mut skipped = vec![0u8; attribute.byte_len()];
+                    reader.read_exact(&mut skipped)?;
+                }
+            }
+        }
+        Ok(point)
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reader for the plain text `.pts` point cloud format: one point per line,
+//! whitespace separated `x y z intensity r g b`.
+
+use crate::math::Vector3f;
+use crate::point::Point;
+use crate::schema::{Attribute, DataType, Schema};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+pub struct PtsPointStream {
+    lines: std::io::Lines<BufReader<File>>,
+}
+
+impl PtsPointStream {
+    pub fn new<P: AsRef<Path>>(filename: P) -> Self {
+        let reader = BufReader::new(File::open(filename).unwrap());
+        PtsPointStream {
+            lines: reader.lines(),
+        }
+    }
+
+    /// `.pts` files always carry `x y z intensity r g b`, so the schema is fixed.
+    pub fn schema(&self) -> Schema {
+        Schema::new(vec![
+            Attribute::new("position", DataType::F32, 3),
+            Attribute::new("intensity", DataType::F32, 1),
+            Attribute::new("color", DataType::U8, 3),
+        ])
+    }
+}
+
+impl Iterator for PtsPointStream {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        loop {
+            let line = self.lines.next()?.unwrap();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // The first line of a .pts file is the point count, skip it.
+            if fields.len() < 7 {
+                continue;
+            }
+            let parse = |s: &str| s.parse::<f32>().unwrap();
+            return Some(Point {
+                position: Vector3f::new(parse(fields[0]), parse(fields[1]), parse(fields[2])),
+                intensity: Some(parse(fields[3])),
+                r: fields[4].parse().unwrap(),
+                g: fields[5].parse().unwrap(),
+                b: fields[6].parse().unwrap(),
+                ..Point::default()
+            });
+        }
+    }
+}
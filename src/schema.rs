@@ -0,0 +1,143 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A declared, self-describing layout for the per-point attributes stored in an octree node
+//! blob. `NodeWriter` and `octree::PointStream` read and write records generically by walking a
+//! `Schema` instead of assuming a fixed position+color record, so formats that carry intensity,
+//! normals, GPS time or classification do not have to drop them on the way into the octree.
+
+use std::io::{self, Read, Write};
+
+/// The primitive on-disk representation of a single attribute component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    F32,
+    F64,
+    U8,
+}
+
+impl DataType {
+    fn byte_len(self) -> usize {
+        match self {
+            DataType::F32 => 4,
+            DataType::F64 => 8,
+            DataType::U8 => 1,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            DataType::F32 => "f32",
+            DataType::F64 => "f64",
+            DataType::U8 => "u8",
+        }
+    }
+
+    fn from_name(name: &str) -> Self {
+        match name {
+            "f32" => DataType::F32,
+            "f64" => DataType::F64,
+            "u8" => DataType::U8,
+            other => panic!("Unknown attribute data type: {}", other),
+        }
+    }
+}
+
+/// A single named attribute, e.g. `("position", F32, 3)` or `("intensity", F32, 1)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub data_type: DataType,
+    pub count: usize,
+}
+
+impl Attribute {
+    pub fn new(name: &str, data_type: DataType, count: usize) -> Self {
+        Attribute {
+            name: name.to_string(),
+            data_type,
+            count,
+        }
+    }
+
+    pub(crate) fn byte_len(&self) -> usize {
+        self.data_type.byte_len() * self.count
+    }
+}
+
+/// The ordered list of attributes that make up a single point record on disk. The byte stride of
+/// a record is the sum of its attributes' byte lengths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    pub attributes: Vec<Attribute>,
+}
+
+impl Schema {
+    pub fn new(attributes: Vec<Attribute>) -> Self {
+        Schema { attributes }
+    }
+
+    /// The fixed 15-byte position+color record that every octree used before attributes became
+    /// pluggable. Kept around so octrees written by older versions still load.
+    pub fn legacy() -> Self {
+        Schema::new(vec![
+            Attribute::new("position", DataType::F32, 3),
+            Attribute::new("color", DataType::U8, 3),
+        ])
+    }
+
+    pub fn stride(&self) -> usize {
+        self.attributes.iter().map(Attribute::byte_len).sum()
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.attributes.iter().any(|a| a.name == name)
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut array = json::JsonValue::new_array();
+        for attribute in &self.attributes {
+            array
+                .push(object!{
+                    "name" => attribute.name.clone(),
+                    "data_type" => attribute.data_type.name(),
+                    "count" => attribute.count as u32,
+                })
+                .unwrap();
+        }
+        array
+    }
+
+    pub fn from_json(value: &json::JsonValue) -> Self {
+        let mut attributes = Vec::new();
+        for entry in value.members() {
+            attributes.push(Attribute {
+                name: entry["name"].as_str().unwrap().to_string(),
+                data_type: DataType::from_name(entry["data_type"].as_str().unwrap()),
+                count: entry["count"].as_usize().unwrap(),
+            });
+        }
+        Schema::new(attributes)
+    }
+}
+
+/// A value that can write itself as the schema declares it - as opposed to a fixed byte layout.
+pub trait ToWriter {
+    fn to_writer<W: Write>(&self, schema: &Schema, writer: &mut W) -> io::Result<()>;
+}
+
+/// The counterpart of `ToWriter`: reconstructs a value from a schema-described record.
+pub trait FromReader: Sized {
+    fn from_reader<R: Read>(schema: &Schema, reader: &mut R) -> io::Result<Self>;
+}
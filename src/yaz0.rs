@@ -0,0 +1,182 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal implementation of the Yaz0 run-length/LZ container used to
+//! transparently compress octree node blobs on disk.
+//!
+//! The container is a 4-byte magic `"Yaz0"`, a 4-byte big-endian
+//! uncompressed length, 8 reserved zero bytes, followed by a stream of
+//! chunks. Each chunk starts with one code byte whose bits are consumed
+//! MSB-first: a `1` bit means "copy the next literal byte to the output",
+//! a `0` bit means "read a back-reference" as `(b0, b1)` (and optionally a
+//! third length byte), see `decompress` below for the exact encoding.
+
+const MAGIC: &[u8; 4] = b"Yaz0";
+const HEADER_LEN: usize = 16;
+
+/// The minimum match length worth encoding as a back-reference.
+const MIN_MATCH_LEN: usize = 3;
+/// The longest match length a single back-reference chunk can encode.
+const MAX_MATCH_LEN: usize = 0xFF + 0x12;
+/// The largest distance a back-reference can reach.
+const MAX_DISTANCE: usize = 0x1000;
+
+/// Returns true if `data` starts with the Yaz0 magic.
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && &data[0..4] == MAGIC
+}
+
+/// Compresses `data` into a Yaz0 container using a simple sliding-window
+/// match finder.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(HEADER_LEN + data.len());
+    output.extend_from_slice(MAGIC);
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(&[0u8; 8]);
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut code_byte = 0u8;
+        let code_byte_pos = output.len();
+        output.push(0); // Placeholder, filled in below.
+
+        for bit in 0..8 {
+            if pos >= data.len() {
+                break;
+            }
+            match find_longest_match(data, pos) {
+                Some((distance, length)) => {
+                    let disp = distance - 1;
+                    if length - 2 <= 0xF {
+                        output.push((((length - 2) << 4) as u8) | ((disp >> 8) as u8));
+                        output.push((disp & 0xFF) as u8);
+                    } else {
+                        output.push((disp >> 8) as u8);
+                        output.push((disp & 0xFF) as u8);
+                        output.push((length - 0x12) as u8);
+                    }
+                    pos += length;
+                }
+                None => {
+                    code_byte |= 1 << (7 - bit);
+                    output.push(data[pos]);
+                    pos += 1;
+                }
+            }
+        }
+        output[code_byte_pos] = code_byte;
+    }
+    output
+}
+
+/// Finds the longest match for the bytes starting at `pos` among the
+/// previous `MAX_DISTANCE` bytes of `data`. Returns `(distance, length)`.
+fn find_longest_match(data: &[u8], pos: usize) -> Option<(usize, usize)> {
+    let window_start = pos.saturating_sub(MAX_DISTANCE);
+    let max_len = MAX_MATCH_LEN.min(data.len() - pos);
+    if max_len < MIN_MATCH_LEN {
+        return None;
+    }
+
+    let mut best_len = 0;
+    let mut best_distance = 0;
+    for candidate in window_start..pos {
+        let mut len = 0;
+        while len < max_len && data[candidate + len] == data[pos + len] {
+            len += 1;
+        }
+        if len > best_len {
+            best_len = len;
+            best_distance = pos - candidate;
+        }
+    }
+
+    if best_len >= MIN_MATCH_LEN {
+        Some((best_distance, best_len))
+    } else {
+        None
+    }
+}
+
+/// Decompresses a Yaz0 container produced by `compress` (or any compatible
+/// encoder) back into the original bytes.
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    assert!(is_compressed(data), "not a Yaz0 container");
+    let uncompressed_len =
+        u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+
+    let mut output = Vec::with_capacity(uncompressed_len);
+    let mut pos = HEADER_LEN;
+    while output.len() < uncompressed_len {
+        let code_byte = data[pos];
+        pos += 1;
+        for bit in 0..8 {
+            if output.len() >= uncompressed_len {
+                break;
+            }
+            if code_byte & (1 << (7 - bit)) != 0 {
+                output.push(data[pos]);
+                pos += 1;
+            } else {
+                let b0 = data[pos] as usize;
+                let b1 = data[pos + 1] as usize;
+                pos += 2;
+                let n = b0 >> 4;
+                let length = if n != 0 {
+                    n + 2
+                } else {
+                    let b2 = data[pos] as usize;
+                    pos += 1;
+                    b2 + 0x12
+                };
+                let distance = (((b0 & 0x0F) << 8) | b1) + 1;
+
+                let start = output.len() - distance;
+                for i in 0..length {
+                    let byte = output[start + i];
+                    output.push(byte);
+                }
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips_arbitrary_data() {
+        let original: Vec<u8> = (0..5000u32).map(|i| (i % 17) as u8).collect();
+        let compressed = compress(&original);
+        assert!(is_compressed(&compressed));
+        assert_eq!(decompress(&compressed), original);
+    }
+
+    #[test]
+    fn roundtrips_incompressible_data() {
+        let original: Vec<u8> = (0..300u32)
+            .map(|i| (i.wrapping_mul(2654435761) % 256) as u8)
+            .collect();
+        let compressed = compress(&original);
+        assert_eq!(decompress(&compressed), original);
+    }
+
+    #[test]
+    fn roundtrips_empty_input() {
+        let compressed = compress(&[]);
+        assert_eq!(decompress(&compressed), Vec::<u8>::new());
+    }
+}